@@ -11,16 +11,25 @@ use vst::api;
 use vst::buffer::{ SendEventBuffer};
 use vst::event::{Event, MidiEvent};
 use vst::plugin::{CanDo, HostCallback,};
+use vst::host::Host;
+use vst::api::{TimeInfo, TimeInfoFlags};
 use std::sync::Arc;
+use rand::Rng;
 use rand_distr::{Normal, Distribution};
 
 
 /**
  * Parameters
- */ 
+ */
 struct VaryVelocityParameters {
     variance: AtomicFloat,
-    minimum: AtomicFloat
+    minimum: AtomicFloat,
+    repetition: AtomicFloat,
+    mode: AtomicFloat,
+    maximum: AtomicFloat,
+    timing: AtomicFloat,
+    accent_amount: AtomicFloat,
+    accent_subdivision: AtomicFloat
 }
 
 
@@ -28,7 +37,13 @@ impl Default for VaryVelocityParameters {
     fn default() -> VaryVelocityParameters {
         VaryVelocityParameters {
             variance: AtomicFloat::new(0.0),
-            minimum: AtomicFloat::new(0.0)
+            minimum: AtomicFloat::new(0.0),
+            repetition: AtomicFloat::new(0.0),
+            mode: AtomicFloat::new(0.0),
+            maximum: AtomicFloat::new(1.0),
+            timing: AtomicFloat::new(0.0),
+            accent_amount: AtomicFloat::new(0.0),
+            accent_subdivision: AtomicFloat::new(0.0)
         }
     }
 }
@@ -36,6 +51,38 @@ impl Default for VaryVelocityParameters {
 
 static MAX_VARIANCE: f32 = 25.;
 static MAX_MINIMUM: f32 = 127.;
+static MAX_REPETITION: f32 = 10.;
+static MAX_MAXIMUM: f32 = 127.;
+// Spread (standard deviation of the half-normal delay), in milliseconds, at
+// the top of the parameter range; single delays can exceed it.
+static MAX_TIMING: f32 = 50.;
+
+static NUM_PARAMS: i32 = 8;
+// Bump this whenever a parameter is added or reordered so old saved
+// presets aren't misread as the new layout.
+static PRESET_FORMAT_VERSION: u8 = 2;
+
+static MAX_ACCENT: f32 = 25.;
+// Accent subdivisions per beat, e.g. 1 accents quarter notes, 4 accents
+// sixteenth notes.
+static MAX_ACCENT_SUBDIVISION: f32 = 8.;
+
+
+#[derive(PartialEq)]
+enum DistributionMode {
+    Normal,
+    Uniform,
+}
+
+impl DistributionMode {
+    fn from_param(val: f32) -> DistributionMode {
+        if val < 0.5 {
+            DistributionMode::Normal
+        } else {
+            DistributionMode::Uniform
+        }
+    }
+}
 
 
 /**
@@ -49,6 +96,10 @@ struct VaryVelocity {
     immediate_events: Vec<MidiEvent>,
     send_buffer: SendEventBuffer,
     params: Arc<VaryVelocityParameters>,
+    last_val: u8,
+    counter: u8,
+    sample_counter: u64,
+    scheduled_events: Vec<(u64, MidiEvent)>,
 }
 
 
@@ -57,21 +108,94 @@ impl VaryVelocity {
         let velocity = e.data[2];
         let variance = self.params.variance.get() * MAX_VARIANCE;
         let minimum = self.params.minimum.get() * MAX_MINIMUM;
+        let maximum = self.params.maximum.get() * MAX_MAXIMUM;
+        let repetition = (self.params.repetition.get() * MAX_REPETITION).round();
+        let mode = DistributionMode::from_param(self.params.mode.get());
 
-        let normal = Normal::new(velocity as f32, variance).unwrap();
-        let v = normal.sample(&mut rand::thread_rng()).max(minimum).min(127.) as f32;
+        let v = if self.counter as f32 >= repetition {
+            let v = match mode {
+                DistributionMode::Normal => {
+                    let (min, max) = if minimum <= maximum { (minimum, maximum) } else { (maximum, minimum) };
+                    let normal = Normal::new(velocity as f32, variance).unwrap();
+                    normal.sample(&mut rand::thread_rng()).max(min).min(max).min(127.) as u8
+                }
+                DistributionMode::Uniform => {
+                    let (min, max) = if minimum <= maximum { (minimum, maximum) } else { (maximum, minimum) };
+                    if min < max {
+                        rand::thread_rng().gen_range(min..max) as u8
+                    } else {
+                        min as u8
+                    }
+                }
+            };
+            self.last_val = v;
+            self.counter = 0;
+            v
+        } else {
+            self.counter += 1;
+            self.last_val
+        };
 
-        self.immediate_events.push(MidiEvent {
-            data: [e.data[0], e.data[1], v as u8],
+        let v = (v as f32 + self.accent_offset()).clamp(0., 127.) as u8;
+
+        let timing = self.params.timing.get() * MAX_TIMING;
+        let offset_ms = Normal::new(0.0, timing.max(0.0000000001))
+            .unwrap()
+            .sample(&mut rand::thread_rng())
+            .abs();
+        let offset_frames = (offset_ms / 1000. * self.sample_rate) as u64;
+        let target = self.sample_counter + e.delta_frames as u64 + offset_frames;
+
+        self.scheduled_events.push((target, MidiEvent {
+            data: [e.data[0], e.data[1], v],
             ..e
-        });
+        }));
     }
-    
+
     fn send_midi(&mut self) {
         // Immediate
         self.send_buffer.send_events(&self.immediate_events, &mut self.host);
         self.immediate_events.clear();
     }
+
+    // Boosts velocity on notes that land on the accent grid (each beat split
+    // into `subdivision` parts) and reduces it on notes between grid points,
+    // using the host's transport position. Falls back to no accent at all
+    // when the host can't provide valid position, bar or time signature info.
+    fn accent_offset(&self) -> f32 {
+        let amount = self.params.accent_amount.get() * MAX_ACCENT;
+        if amount <= 0. {
+            return 0.;
+        }
+
+        let subdivision = (self.params.accent_subdivision.get() * MAX_ACCENT_SUBDIVISION)
+            .round()
+            .max(1.);
+
+        let mask = (TimeInfoFlags::PPQ_POS_VALID | TimeInfoFlags::TIME_SIG_VALID | TimeInfoFlags::BARS_VALID).bits();
+        let info: TimeInfo = match self.host.get_time_info(mask) {
+            Some(info) => info,
+            None => return 0.,
+        };
+
+        let flags = TimeInfoFlags::from_bits_truncate(info.flags);
+        if !flags.contains(TimeInfoFlags::PPQ_POS_VALID)
+            || !flags.contains(TimeInfoFlags::TIME_SIG_VALID)
+            || !flags.contains(TimeInfoFlags::BARS_VALID)
+        {
+            return 0.;
+        }
+
+        let beat_len = 4. / info.time_sig_denominator as f64;
+        let bar_len = beat_len * info.time_sig_numerator as f64;
+        let pos_in_bar = (info.ppq_pos - info.bar_start_pos).rem_euclid(bar_len);
+
+        let subdivision_len = beat_len / subdivision as f64;
+        let phase = (pos_in_bar / subdivision_len).rem_euclid(1.0);
+        let on_grid = !(0.05..=0.95).contains(&phase);
+
+        if on_grid { amount } else { -amount }
+    }
 }
 
 impl Plugin for VaryVelocity {
@@ -79,6 +203,9 @@ impl Plugin for VaryVelocity {
         let mut p = VaryVelocity::default();
         p.host = host;
         p.params = Arc::new(VaryVelocityParameters::default());
+        // Force a fresh sample on the very first note instead of reusing
+        // an uninitialized last_val.
+        p.counter = u8::MAX;
         p
     }
 
@@ -92,7 +219,11 @@ impl Plugin for VaryVelocity {
             outputs: 2,
             // This `parameters` bit is important; without it, none of our
             // parameters will be shown!
-            parameters: 2,
+            parameters: 8,
+            // Without this, hosts persist/restore state via plain
+            // per-index get_parameter/set_parameter and never call our
+            // preset/bank chunk methods.
+            preset_chunks: true,
             category: Category::Effect,
             ..Default::default()
         }
@@ -119,7 +250,21 @@ impl Plugin for VaryVelocity {
                 *out_sample = *in_sample;
             }
         }
+
+        let block_start = self.sample_counter;
+        let block_end = block_start + buffer.samples() as u64;
+
+        // Keep delta_frames ascending within the block: sort by target
+        // sample before splitting off the due events.
+        self.scheduled_events.sort_by_key(|(target, _)| *target);
+        let due = self.scheduled_events.partition_point(|(target, _)| *target < block_end);
+        for (target, mut event) in self.scheduled_events.drain(0..due) {
+            event.delta_frames = target.saturating_sub(block_start) as i32;
+            self.immediate_events.push(event);
+        }
+
         self.send_midi();
+        self.sample_counter = block_end;
     }
 
     fn can_do(&self, can_do: CanDo) -> vst::api::Supported {
@@ -146,6 +291,12 @@ impl PluginParameters for VaryVelocityParameters {
         match index {
             0 => self.variance.get(),
             1 => self.minimum.get(),
+            2 => self.repetition.get(),
+            3 => self.mode.get(),
+            4 => self.maximum.get(),
+            5 => self.timing.get(),
+            6 => self.accent_amount.get(),
+            7 => self.accent_subdivision.get(),
             _ => 0.0,
         }
     }
@@ -156,6 +307,12 @@ impl PluginParameters for VaryVelocityParameters {
         match index {
             0 => self.variance.set(val.max(0.0000000001)),
             1 => self.minimum.set(val),
+            2 => self.repetition.set(val),
+            3 => self.mode.set(val),
+            4 => self.maximum.set(val),
+            5 => self.timing.set(val),
+            6 => self.accent_amount.set(val),
+            7 => self.accent_subdivision.set(val),
             _ => (),
         }
     }
@@ -166,6 +323,15 @@ impl PluginParameters for VaryVelocityParameters {
         match index {
             0 =>  format!("{:.1}", self.variance.get() * MAX_VARIANCE),
             1 =>  format!("{:}", self.variance.get() * MAX_VARIANCE),
+            2 =>  format!("{:.0}", (self.repetition.get() * MAX_REPETITION).round()),
+            3 => match DistributionMode::from_param(self.mode.get()) {
+                DistributionMode::Normal => "Normal".to_string(),
+                DistributionMode::Uniform => "Uniform".to_string(),
+            },
+            4 =>  format!("{:.1}", self.maximum.get() * MAX_MAXIMUM),
+            5 =>  format!("{:.1} ms", self.timing.get() * MAX_TIMING),
+            6 =>  format!("{:.1}", self.accent_amount.get() * MAX_ACCENT),
+            7 =>  format!("1/{:.0}", (self.accent_subdivision.get() * MAX_ACCENT_SUBDIVISION).round().max(1.)),
             _ => "".to_string(),
         }
     }
@@ -175,10 +341,43 @@ impl PluginParameters for VaryVelocityParameters {
         match index {
             0 => "Velocity variance",
             1 => "Minimum velocity",
+            2 => "Repetition",
+            3 => "Distribution mode",
+            4 => "Maximum velocity",
+            5 => "Timing variance",
+            6 => "Accent amount",
+            7 => "Accent subdivision",
             _ => "",
         }
         .to_string()
     }
+
+    // Serialize all parameters into a versioned blob so the host can
+    // restore them on project reload or preset recall.
+    fn get_preset_data(&self) -> Vec<u8> {
+        let mut data = vec![PRESET_FORMAT_VERSION];
+        for i in 0..NUM_PARAMS {
+            data.extend_from_slice(&self.get_parameter(i).to_le_bytes());
+        }
+        data
+    }
+
+    fn get_bank_data(&self) -> Vec<u8> {
+        self.get_preset_data()
+    }
+
+    fn load_preset_data(&self, data: &[u8]) {
+        if data.is_empty() || data[0] != PRESET_FORMAT_VERSION {
+            return;
+        }
+        for (i, chunk) in data[1..].chunks_exact(4).enumerate() {
+            self.set_parameter(i as i32, f32::from_le_bytes(chunk.try_into().unwrap()));
+        }
+    }
+
+    fn load_bank_data(&self, data: &[u8]) {
+        self.load_preset_data(data);
+    }
 }
 
 // This part is important!  Without it, our plugin won't work.